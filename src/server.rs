@@ -1,14 +1,23 @@
 use anyhow::{anyhow, Context as AnyhowCtx, Error};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use httparse::{Request as ReqParser, Status};
 use serde::{Deserialize, Serialize};
-use std::io::{prelude::*, BufReader};
+use sha1::{Digest, Sha1};
+use std::io::prelude::*;
 use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
 
 use crate::cut_log::CutLog;
 use crate::edl::{AVChannels, Edit, Edl};
 use crate::ltc_decode::{DecodeErr, DecodeHandlers, LTCListener};
 use crate::Opt;
 
+/// The GUID RFC 6455 defines for computing `Sec-WebSocket-Accept` from the
+/// client's `Sec-WebSocket-Key`.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
 pub struct Server<'a> {
     port: String,
     opt: &'a Opt,
@@ -25,61 +34,526 @@ impl<'a> Server<'a> {
     pub fn listen(&mut self) -> Result<(), Error> {
         let listener =
             TcpListener::bind(&self.port).context("Server could not initate TCP connection")?;
-        let mut ctx = Context {
-            decode_handlers: LTCListener::new(self.opt)?.listen(),
-            edl: Edl::new(self.opt)?,
-            cut_log: CutLog::new(),
+        // Without a configured origin, only `http://<bound address>` is
+        // allowed: a page served from any other origin gets no
+        // `Access-Control-Allow-Origin` header and is refused on `/stream`.
+        let cors_allowed_origin = self
+            .opt
+            .cors_allowed_origin
+            .clone()
+            .unwrap_or_else(|| format!("http://{}", self.port));
+        let (command_tx, command_rx) = mpsc::channel();
+        let ctx = Context {
+            decode_handlers: Mutex::new(LTCListener::new(self.opt)?.listen()),
+            edl: Mutex::new(Edl::new(self.opt)?),
+            cut_log: Mutex::new(CutLog::new()),
+            cors_allowed_origin,
+            stream_subscribers: Mutex::new(Vec::new()),
+            frame_commands: Mutex::new(command_tx),
         };
 
         println!("listening on {}", &self.port);
 
-        for stream in listener.incoming() {
-            self.handle_connection(stream?, &mut ctx)
-                .unwrap_or_else(|e| {
-                    eprintln!("Request could not be sent: {:#}", e);
+        thread::scope(|scope| {
+            let ctx = &ctx;
+            scope.spawn(move || {
+                pump_frames(ctx, command_rx).unwrap_or_else(|e| {
+                    eprintln!("Frame pump stopped: {:#}", e);
                 });
-        }
+            });
+
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Could not accept connection: {:#}", e);
+                        continue;
+                    }
+                };
+                scope.spawn(move || {
+                    handle_connection(stream, ctx).unwrap_or_else(|e| {
+                        eprintln!("Request could not be sent: {:#}", e);
+                    });
+                });
+            }
+        });
 
         Ok(())
     }
+}
+
+/// Handles a single client connection end-to-end: read the request, route
+/// it against the shared `ctx`, write the response. Spawned per connection
+/// so one slow or hung client can't stall the LTC decoder or other clients,
+/// which only ever contend on `ctx`'s individual locks rather than the
+/// whole server.
+fn handle_connection(mut stream: TcpStream, ctx: &Context) -> Result<(), Error> {
+    let buffer = match read_request(&mut stream) {
+        Ok(buffer) => buffer,
+        Err(ReadErr::TooLarge) => return write_response(&mut stream, payload_too_large()),
+        Err(ReadErr::Malformed(msg)) => {
+            eprintln!("Malformed request: {}", msg);
+            return write_response(&mut stream, bad_request());
+        }
+        Err(ReadErr::Io(e)) => return Err(e.into()),
+    };
+
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let mut parser = ReqParser::new(&mut headers);
+    let mut req = Request::new(&mut parser, &buffer)?;
+
+    if req.method == Some("GET") && req.path == Some("/stream") && req.is_upgrade() {
+        if !req.origin_is_allowed(ctx) {
+            return write_response(&mut stream, forbidden());
+        }
+        let ws_key = match req.websocket_key() {
+            Ok(ws_key) => ws_key,
+            Err(e) => {
+                eprintln!("Malformed upgrade request: {:#}", e);
+                return write_response(&mut stream, bad_request());
+            }
+        };
+        return stream_timecode(ws_key, stream, ctx);
+    }
+
+    if req.method == Some("OPTIONS") {
+        let mut response = cors_preflight();
+        response.headers.extend(req.cors_headers(ctx));
+        return write_response(&mut stream, response);
+    }
+
+    let mut response = req.route(ctx).unwrap_or_else(|e| {
+        eprintln!("Error processing request: {:#}", e);
+        server_err()
+    });
+    response.headers.extend(req.cors_headers(ctx));
+
+    write_response(&mut stream, response)
+}
+
+/// Locks a piece of shared server state, turning lock poisoning into a
+/// regular `anyhow::Error` so callers can keep using `?` the same way they
+/// do for every other fallible operation in this module.
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<T>, Error> {
+    mutex.lock().map_err(|e| anyhow!("Lock was poisoned: {}", e))
+}
+
+/// Identifies which `Request` method handles a matched route. A plain
+/// data table of these (rather than a nested `match`) is what lets `route`
+/// share one 404/405 fallback across every path.
+enum Handler {
+    Start,
+    Stop,
+    LogEdit,
+    GetLog,
+    GetEdl,
+    DeleteLog,
+}
+
+/// The server's routes: method, path pattern (`:name` segments are
+/// captured and passed to the handler), handler.
+const ROUTES: &[(&str, &str, Handler)] = &[
+    ("POST", "/start", Handler::Start),
+    ("POST", "/stop", Handler::Stop),
+    ("POST", "/log", Handler::LogEdit),
+    ("GET", "/log", Handler::GetLog),
+    ("GET", "/edl", Handler::GetEdl),
+    ("DELETE", "/log/:index", Handler::DeleteLog),
+];
+
+/// Matches a route `pattern` such as `/log/:index` against a request
+/// `path`, returning the captured `:param` segments in pattern order.
+fn match_path<'p>(pattern: &str, path: &'p str) -> Option<Vec<&'p str>> {
+    let pattern_segments: Vec<&str> = pattern.split('/').collect();
+    let path_segments: Vec<&str> = path.split('/').collect();
+    if pattern_segments.len() != path_segments.len() {
+        return None;
+    }
+
+    let mut params = Vec::new();
+    for (pattern_seg, path_seg) in pattern_segments.iter().zip(path_segments.iter()) {
+        if pattern_seg.starts_with(':') {
+            params.push(*path_seg);
+        } else if pattern_seg != path_seg {
+            return None;
+        }
+    }
+    Some(params)
+}
+
+/// Maximum number of bytes buffered for a single request before it is
+/// rejected with a `413`, guarding against unbounded memory growth while a
+/// body trickles in across many TCP segments.
+const MAX_BUFFER_SIZE: usize = 128 * 1024;
+
+enum ReadErr {
+    Io(std::io::Error),
+    Malformed(String),
+    TooLarge,
+}
+
+impl From<ReadErr> for Error {
+    fn from(e: ReadErr) -> Self {
+        match e {
+            ReadErr::Io(e) => anyhow!("Could not read request: {}", e),
+            ReadErr::Malformed(msg) => anyhow!("Could not parse request: {}", msg),
+            ReadErr::TooLarge => anyhow!("Request exceeded maximum buffer size"),
+        }
+    }
+}
+
+/// Reads a full HTTP request off `stream`, looping over however many TCP
+/// reads it takes for `httparse` to report the headers complete and then
+/// for the body to fully arrive. A single `fill_buf` call is not enough in
+/// general: `Status::Partial` means the headers (or body) straddle more
+/// than one segment. `Content-Length` is the fast path; `Transfer-Encoding:
+/// chunked` is decoded into the same shape so `Request::body` doesn't need
+/// to care which one a given client used.
+fn read_request(stream: &mut TcpStream) -> Result<Vec<u8>, ReadErr> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let (header_offset, content_length, is_chunked) = loop {
+        read_more(stream, &mut buffer, &mut chunk)?;
 
-    fn handle_connection(&mut self, mut stream: TcpStream, ctx: &mut Context) -> Result<(), Error> {
-        let mut buf_reader = BufReader::new(&mut stream);
         let mut headers = [httparse::EMPTY_HEADER; 16];
+        match ReqParser::new(&mut headers).parse(&buffer) {
+            Ok(Status::Complete(offset)) => {
+                let content_length = headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|header| std::str::from_utf8(header.value).ok())
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .unwrap_or(0);
+                let is_chunked = headers.iter().any(|header| {
+                    header.name.eq_ignore_ascii_case("transfer-encoding")
+                        && std::str::from_utf8(header.value)
+                            .map(|value| value.to_lowercase().contains("chunked"))
+                            .unwrap_or(false)
+                });
+                break (offset, content_length, is_chunked);
+            }
+            Ok(Status::Partial) => continue,
+            Err(e) => return Err(ReadErr::Malformed(e.to_string())),
+        }
+    };
 
-        let res: SerializedResponse =
-            Request::new(&mut ReqParser::new(&mut headers), buf_reader.fill_buf()?)?
-                .route(ctx)
-                .unwrap_or_else(|e| {
-                    eprintln!("Error processing request: {:#}", e);
-                    server_err()
-                })
-                .parse_to_json()?
-                .into();
+    if is_chunked {
+        let body = read_chunked_body(stream, &mut buffer, &mut chunk, header_offset)?;
+        buffer.truncate(header_offset);
+        buffer.extend_from_slice(&body);
+        return Ok(buffer);
+    }
 
-        stream.write_all(res.value.as_bytes())?;
+    let body_end = header_offset
+        .checked_add(content_length)
+        .ok_or_else(|| ReadErr::Malformed("'Content-Length' overflows".to_string()))?;
+    if body_end > MAX_BUFFER_SIZE {
+        return Err(ReadErr::TooLarge);
+    }
+    while buffer.len() < body_end {
+        read_more(stream, &mut buffer, &mut chunk)?;
+    }
 
-        Ok(())
+    Ok(buffer)
+}
+
+/// Decodes an RFC 7230 §4.1 chunked body: a hex chunk-size line, that many
+/// body bytes, a trailing CRLF, repeated until the zero-length terminating
+/// chunk. `buffer` already holds everything read off `stream` so far, with
+/// the chunk-encoded data starting at `body_start`; more is pulled from
+/// `stream` as each chunk-size line and chunk is parsed.
+fn read_chunked_body(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    chunk: &mut [u8],
+    body_start: usize,
+) -> Result<Vec<u8>, ReadErr> {
+    let mut pos = body_start;
+    let mut body = Vec::new();
+
+    loop {
+        let line_end = loop {
+            if let Some(offset) = buffer[pos..].windows(2).position(|w| w == b"\r\n") {
+                break pos + offset;
+            }
+            read_more(stream, buffer, chunk)?;
+        };
+
+        let size_line = std::str::from_utf8(&buffer[pos..line_end])
+            .map_err(|e| ReadErr::Malformed(format!("chunk size line is not valid UTF-8: {e}")))?;
+        let size_str = size_line.split(';').next().unwrap_or(size_line).trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|e| ReadErr::Malformed(format!("invalid chunk size '{size_str}': {e}")))?;
+
+        if size > MAX_BUFFER_SIZE {
+            return Err(ReadErr::TooLarge);
+        }
+        let data_start = line_end + 2;
+        let data_end = data_start
+            .checked_add(size)
+            .ok_or_else(|| ReadErr::Malformed(format!("chunk size '{size_str}' overflows")))?;
+        while buffer.len() < data_end + 2 {
+            read_more(stream, buffer, chunk)?;
+        }
+
+        if size == 0 {
+            break;
+        }
+
+        body.extend_from_slice(&buffer[data_start..data_end]);
+        if body.len() > MAX_BUFFER_SIZE {
+            return Err(ReadErr::TooLarge);
+        }
+        pos = data_end + 2;
+    }
+
+    Ok(body)
+}
+
+fn read_more(stream: &mut TcpStream, buffer: &mut Vec<u8>, chunk: &mut [u8]) -> Result<(), ReadErr> {
+    let n = stream.read(chunk).map_err(ReadErr::Io)?;
+    if n == 0 {
+        return Err(ReadErr::Malformed(
+            "connection closed before request was complete".to_string(),
+        ));
     }
+    buffer.extend_from_slice(&chunk[..n]);
+    if buffer.len() > MAX_BUFFER_SIZE {
+        return Err(ReadErr::TooLarge);
+    }
+    Ok(())
+}
+
+fn write_response(stream: &mut TcpStream, response: Response) -> Result<(), Error> {
+    let res: SerializedResponse = response.parse_to_json()?.into();
+    stream.write_all(res.value.as_bytes())?;
+    Ok(())
+}
+
+/// Completes the WebSocket handshake for `GET /stream` and then blocks,
+/// pushing every frame broadcast by [`pump_frames`] to the client as a text
+/// frame until the connection errors or the client disconnects. This does
+/// *not* call `recv_frame`/`try_recv_frame` on `ctx.decode_handlers` itself:
+/// [`pump_frames`] is the channel's only consumer, so subscribing to its
+/// broadcast instead keeps `/stream` from ever competing with `/start`,
+/// `/stop`, or `/log` for a frame.
+///
+/// A subscriber that's still connected but idle (no frames arriving) is
+/// polled on a short timeout rather than blocking on `rx.recv()` forever, so
+/// a client that disconnects before any frame is ever produced is noticed
+/// directly -- via a failed read on its own socket -- instead of leaking its
+/// thread until the next broadcast happens to trip the dead-send cleanup in
+/// `pump_frames`.
+fn stream_timecode(ws_key: &str, mut stream: TcpStream, ctx: &Context) -> Result<(), Error> {
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        websocket_accept_key(ws_key)
+    );
+    stream.write_all(handshake.as_bytes())?;
+    stream.set_read_timeout(Some(Duration::from_millis(200)))?;
+
+    let (tx, rx) = mpsc::channel();
+    lock(&ctx.stream_subscribers)?.push(tx);
+
+    loop {
+        match rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(frame) => {
+                let frame = encode_text_frame(&frame);
+                if stream.write_all(&frame).is_err() {
+                    break;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let mut probe = [0u8; 1];
+                match stream.read(&mut probe) {
+                    Ok(0) => break,
+                    Err(e)
+                        if e.kind() == std::io::ErrorKind::WouldBlock
+                            || e.kind() == std::io::ErrorKind::TimedOut => {}
+                    Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Commands sent to [`pump_frames`] by `/start`, `/stop`, and `/log` instead
+/// of locking `ctx.decode_handlers` themselves. This keeps `pump_frames` the
+/// lock's only caller: a `/start` waiting on the first frame after decoding
+/// begins never holds `decode_handlers`, so `/stop` (or `/log`, or
+/// `/stream`) is never blocked behind it.
+enum FrameCommand {
+    /// `/start`: turn decoding on, clear the cut log, and reply with the
+    /// first frame logged once one arrives.
+    WaitForFirstFrame {
+        edit: EditRequestData,
+        reply: mpsc::Sender<Result<Response, Error>>,
+    },
+    /// `/stop` and `/log`: log whatever frame is available right now, same
+    /// as `EditRequestData::try_log_edit` always has.
+    TryLogEdit {
+        edit: EditRequestData,
+        reply: mpsc::Sender<Result<Response, Error>>,
+    },
+    /// `/stop`: turn decoding off, cancelling a pending `WaitForFirstFrame`
+    /// (if any) so a `/start` that's still waiting on a signal doesn't block
+    /// `/stop` out.
+    DecodeOff { reply: mpsc::Sender<Result<(), Error>> },
+}
+
+fn send_frame_command(ctx: &Context, command: FrameCommand) -> Result<(), Error> {
+    lock(&ctx.frame_commands)?
+        .send(command)
+        .map_err(|_| anyhow!("Frame pump is no longer running"))
+}
+
+/// The sole consumer of `ctx.decode_handlers`: services [`FrameCommand`]s
+/// from `/start`/`/stop`/`/log` and polls with `try_recv_frame` (never
+/// blocks) to fan each frame out to every `/stream` subscriber, dropping
+/// subscribers whose receiver has gone away. Centralizing every read here
+/// -- rather than each endpoint locking `decode_handlers` on its own -- is
+/// what stops `/stream`, `/start`, and `/log` from racing each other for the
+/// same frame.
+fn pump_frames(ctx: &Context, commands: mpsc::Receiver<FrameCommand>) -> Result<(), Error> {
+    let mut pending_start: Option<(EditRequestData, mpsc::Sender<Result<Response, Error>>)> = None;
+
+    loop {
+        for command in commands.try_iter() {
+            match command {
+                FrameCommand::WaitForFirstFrame { edit, reply } => {
+                    if let Err(e) = lock(&ctx.decode_handlers)?.decode_on() {
+                        let _ = reply.send(Err(e.into()));
+                        continue;
+                    }
+                    lock(&ctx.cut_log)?.clear();
+                    println!("wating for audio...");
+                    pending_start = Some((edit, reply));
+                }
+                FrameCommand::TryLogEdit { edit, reply } => {
+                    let _ = reply.send(edit.try_log_edit(ctx));
+                }
+                FrameCommand::DecodeOff { reply } => {
+                    let result = lock(&ctx.decode_handlers)?.decode_off().map_err(Error::from);
+                    if let Some((_, start_reply)) = pending_start.take() {
+                        let _ = start_reply.send(Ok(frame_unavailable()));
+                    }
+                    let _ = reply.send(result);
+                }
+            }
+        }
+
+        let want_frame = pending_start.is_some() || !lock(&ctx.stream_subscribers)?.is_empty();
+        if !want_frame {
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+
+        match lock(&ctx.decode_handlers)?.try_recv_frame() {
+            Ok(tc) => {
+                let frame = format!("{:#?}", tc.timecode());
+                lock(&ctx.stream_subscribers)?.retain(|tx| tx.send(frame.clone()).is_ok());
+
+                if let Some((edit, reply)) = pending_start.take() {
+                    let result = (|| -> Result<Response, Error> {
+                        lock(&ctx.cut_log)?.push(
+                            tc,
+                            &edit.edit_type,
+                            &edit.source_tape,
+                            &edit.av_channel,
+                        )?;
+                        println!("ready!");
+                        Ok(format!("Started decoding. timecode logged: {:#?}", tc.timecode()).into())
+                    })();
+                    let _ = reply.send(result);
+                }
+            }
+            Err(DecodeErr::NoVal(_)) => thread::sleep(Duration::from_millis(50)),
+            Err(e) => return Err(Error::msg(e)),
+        }
+    }
+}
+
+fn websocket_accept_key(client_key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(client_key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Encodes a single unmasked, unfragmented text frame per RFC 6455 section 5.2.
+fn encode_text_frame(payload: &str) -> Vec<u8> {
+    let payload = payload.as_bytes();
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0b1000_0001); // FIN set, opcode 0x1 (text)
+
+    let len = payload.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    frame.extend_from_slice(payload);
+    frame
 }
 
 #[derive(Debug)]
 pub struct Context<'serv> {
-    cut_log: CutLog,
-    decode_handlers: DecodeHandlers<'serv>,
-    edl: Edl,
+    cut_log: Mutex<CutLog>,
+    decode_handlers: Mutex<DecodeHandlers<'serv>>,
+    edl: Mutex<Edl>,
+    /// Origin allowed through CORS, echoed back by `Request::cors_headers`.
+    /// `"*"` allows any origin; anything else must match a request's
+    /// `Origin` header exactly before it's echoed back.
+    cors_allowed_origin: String,
+    /// Senders for every connected `/stream` client, fed by [`pump_frames`].
+    stream_subscribers: Mutex<Vec<mpsc::Sender<String>>>,
+    /// `/start`, `/stop`, and `/log` send [`FrameCommand`]s here instead of
+    /// locking `decode_handlers` themselves, so only [`pump_frames`] ever
+    /// touches it.
+    frame_commands: Mutex<mpsc::Sender<FrameCommand>>,
 }
 
 #[derive(Debug)]
 struct Response {
     content: String,
     status_line: String,
+    /// `true` when `content` is already a serialized JSON value (e.g. a
+    /// `GET /log` listing) and should be sent through as-is, rather than
+    /// quoted into a JSON string the way the plain-text responses are.
+    raw_json: bool,
+    /// Extra headers, e.g. the CORS headers `Request::cors_headers` and
+    /// `cors_preflight` attach, carried through `SerializedResponse`.
+    headers: Vec<(String, String)>,
 }
 
 impl Response {
+    fn json(status_line: &str, content: String) -> Self {
+        Response {
+            status_line: status_line.to_string(),
+            content,
+            raw_json: true,
+            headers: Vec::new(),
+        }
+    }
+
     fn parse_to_json(mut self) -> Result<Self, Error> {
-        self.content =
-            serde_json::to_string(&self.content).context("Could not parse HTTP Response")?;
+        if !self.raw_json {
+            self.content =
+                serde_json::to_string(&self.content).context("Could not parse HTTP Response")?;
+        }
         Ok(self)
     }
 }
@@ -98,8 +572,9 @@ impl<'r> Request<'r> {
         let header_offset = match req.parse(buffer) {
             Ok(Status::Complete(header_offset)) => Ok(header_offset),
 
-            //TODO: this is funky. try with firefox and see.
-            Ok(Status::Partial) => Ok(req.headers.len()),
+            // read_request only ever hands us a buffer once httparse has
+            // already reported the headers complete on it.
+            Ok(Status::Partial) => Err(anyhow!("Headers are unexpectedly incomplete")),
             Err(e) => Err(anyhow!("Could not parse header lenght: {}", e)),
         }?;
 
@@ -112,50 +587,245 @@ impl<'r> Request<'r> {
         })
     }
 
-    fn route(&mut self, ctx: &mut Context) -> Result<Response, Error> {
-        match self.method {
-            Some("POST") => match self.path {
-                Some("/start") => {
-                    ctx.decode_handlers.decode_on()?;
-                    ctx.cut_log.clear();
-                    println!("wating for audio...");
-                    let mut response = self.body()?.wait_for_first_frame(ctx)?;
-                    println!("ready!");
-                    response.content = format!("Started decoding. {}", response.content);
-                    Ok(response)
-                }
-                Some("/stop") => {
-                    ctx.decode_handlers.decode_off()?;
-                    let mut response = self.body()?.try_log_edit(ctx)?;
-                    response.content = format!("Stopped decoding with {}", response.content);
-                    Ok(response)
-                }
-                Some("/log") => self.body()?.try_log_edit(ctx),
-                _ => Ok(not_found()),
+    /// Looks `self.method`/`self.path` up in [`ROUTES`] and dispatches to
+    /// the matching handler. Replaces the old nested
+    /// `match self.method { ... match self.path { ... } }`, which had no
+    /// room for a path carrying a `:param` and duplicated its `404` arm once
+    /// per method. A path that matches some route's pattern but not its
+    /// method reports `405` instead of `404`.
+    fn route(&mut self, ctx: &Context) -> Result<Response, Error> {
+        let method = self.method.unwrap_or_default();
+        let path = self.path.unwrap_or_default();
+
+        let mut path_matched = false;
+        for (route_method, pattern, handler) in ROUTES {
+            let Some(params) = match_path(pattern, path) else {
+                continue;
+            };
+            path_matched = true;
+            if *route_method != method {
+                continue;
+            }
+            return match handler {
+                Handler::Start => self.start(ctx),
+                Handler::Stop => self.stop(ctx),
+                Handler::LogEdit => self.log_edit(ctx),
+                Handler::GetLog => self.get_log(ctx),
+                Handler::GetEdl => self.get_edl(ctx),
+                Handler::DeleteLog => self.delete_log(ctx, &params),
+            };
+        }
+
+        Ok(if path_matched {
+            method_not_allowed()
+        } else {
+            not_found()
+        })
+    }
+
+    /// Parses the request body, translating a malformed or missing body into
+    /// a `400` response instead of bubbling it up as a request-ending error.
+    fn parse_body(&mut self) -> Result<EditRequestData, Response> {
+        self.body().map_err(|e| {
+            eprintln!("Error parsing request body: {:#}", e);
+            bad_request()
+        })
+    }
+
+    /// Sends `/start`'s work to [`pump_frames`] and blocks on its own reply
+    /// channel for the result, rather than locking `ctx.decode_handlers`
+    /// directly -- that lock is [`pump_frames`]'s alone, so a `/start` that's
+    /// still waiting on the first frame never stands in `/stop`'s way.
+    fn start(&mut self, ctx: &Context) -> Result<Response, Error> {
+        let body = match self.parse_body() {
+            Ok(body) => body,
+            Err(resp) => return Ok(resp),
+        };
+        let (reply_tx, reply_rx) = mpsc::channel();
+        send_frame_command(
+            ctx,
+            FrameCommand::WaitForFirstFrame {
+                edit: body,
+                reply: reply_tx,
             },
-            _ => Ok(not_found()),
+        )?;
+        reply_rx.recv().context("Frame pump is no longer running")?
+    }
+
+    fn stop(&mut self, ctx: &Context) -> Result<Response, Error> {
+        let body = match self.parse_body() {
+            Ok(body) => body,
+            Err(resp) => return Ok(resp),
+        };
+        let (off_tx, off_rx) = mpsc::channel();
+        send_frame_command(ctx, FrameCommand::DecodeOff { reply: off_tx })?;
+        off_rx.recv().context("Frame pump is no longer running")??;
+
+        let (reply_tx, reply_rx) = mpsc::channel();
+        send_frame_command(
+            ctx,
+            FrameCommand::TryLogEdit {
+                edit: body,
+                reply: reply_tx,
+            },
+        )?;
+        let mut response = reply_rx.recv().context("Frame pump is no longer running")??;
+        response.content = format!("Stopped decoding with {}", response.content);
+        Ok(response)
+    }
+
+    fn log_edit(&mut self, ctx: &Context) -> Result<Response, Error> {
+        let body = match self.parse_body() {
+            Ok(body) => body,
+            Err(resp) => return Ok(resp),
+        };
+        let (reply_tx, reply_rx) = mpsc::channel();
+        send_frame_command(
+            ctx,
+            FrameCommand::TryLogEdit {
+                edit: body,
+                reply: reply_tx,
+            },
+        )?;
+        reply_rx.recv().context("Frame pump is no longer running")?
+    }
+
+    /// `GET /log` — returns the in-memory cut log as JSON, so an editor UI
+    /// can query what's been logged instead of only ever writing blind.
+    fn get_log(&mut self, ctx: &Context) -> Result<Response, Error> {
+        let cut_log = lock(&ctx.cut_log)?;
+        let content = serde_json::to_string(&*cut_log).context("Could not serialize cut log")?;
+        Ok(Response::json("HTTP/1.1 200 OK", content))
+    }
+
+    /// `GET /edl` — returns the EDL built so far as JSON.
+    fn get_edl(&mut self, ctx: &Context) -> Result<Response, Error> {
+        let edl = lock(&ctx.edl)?;
+        let content = serde_json::to_string(&*edl).context("Could not serialize EDL")?;
+        Ok(Response::json("HTTP/1.1 200 OK", content))
+    }
+
+    /// `DELETE /log/:index` — drops a mis-logged cut from the in-memory log.
+    fn delete_log(&mut self, ctx: &Context, params: &[&str]) -> Result<Response, Error> {
+        let index = match params.first().and_then(|param| param.parse::<usize>().ok()) {
+            Some(index) => index,
+            None => return Ok(bad_request()),
+        };
+
+        let mut cut_log = lock(&ctx.cut_log)?;
+        match cut_log.remove(index) {
+            Some(_) => Ok(format!("removed cut at index {index}").into()),
+            None => Ok(not_found()),
         }
     }
 
-    fn body(&mut self) -> Result<EditRequestData, Error> {
-        let body_length = self
-            .headers
+    /// Detects an HTTP/1.1 Upgrade handshake, i.e. a `Connection: Upgrade`
+    /// header alongside `Upgrade: websocket`, per RFC 6455 section 4.1.
+    fn is_upgrade(&self) -> bool {
+        let has_header = |name: &str, needle: &str| {
+            self.headers.iter().any(|header| {
+                header.name.eq_ignore_ascii_case(name)
+                    && std::str::from_utf8(header.value)
+                        .map(|value| value.to_lowercase().contains(needle))
+                        .unwrap_or(false)
+            })
+        };
+        has_header("connection", "upgrade") && has_header("upgrade", "websocket")
+    }
+
+    fn websocket_key(&self) -> Result<&'r str, Error> {
+        self.headers
             .iter()
-            .find(|header| header.name.to_lowercase() == "content-length")
-            .ok_or_else(|| anyhow!("'Content-Length' header is missing"))
+            .find(|header| header.name.eq_ignore_ascii_case("sec-websocket-key"))
+            .ok_or_else(|| anyhow!("'Sec-WebSocket-Key' header is missing"))
             .and_then(|header| {
                 std::str::from_utf8(header.value)
-                    .context("'Content-Length' header is not valid UTF-8")
+                    .context("'Sec-WebSocket-Key' header is not valid UTF-8")
             })
-            .and_then(|header| {
-                header
-                    .parse::<usize>()
-                    .context("'Content-Length' header is not a valid number")
-            })?;
-
-        let body_start = self.header_offset;
-        let body_end = body_start + body_length;
-        let body = &self.buffer[body_start..body_end];
+    }
+
+    /// `read_request` already decodes a `Transfer-Encoding: chunked` body
+    /// down to raw bytes appended after the headers, so the only thing this
+    /// needs to know is whether to trust `Content-Length` or just take the
+    /// rest of the buffer.
+    fn is_chunked(&self) -> bool {
+        self.headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case("transfer-encoding")
+                && std::str::from_utf8(header.value)
+                    .map(|value| value.to_lowercase().contains("chunked"))
+                    .unwrap_or(false)
+        })
+    }
+
+    /// The request's `Origin` header, if present.
+    fn origin(&self) -> Option<&'r str> {
+        self.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("origin"))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
+    }
+
+    /// Builds the `Access-Control-Allow-Origin` header for this request, if
+    /// any. Only ever echoes an origin the server is actually configured to
+    /// allow: `ctx.cors_allowed_origin == "*"` allows anything, otherwise the
+    /// request's `Origin` must match it exactly, per the actix changelog note
+    /// against blindly reflecting `*` back to the client.
+    fn cors_headers(&self, ctx: &Context) -> Vec<(String, String)> {
+        let Some(origin) = self.origin() else {
+            return Vec::new();
+        };
+        if ctx.cors_allowed_origin == "*" || ctx.cors_allowed_origin == origin {
+            vec![(
+                "Access-Control-Allow-Origin".to_string(),
+                ctx.cors_allowed_origin.clone(),
+            )]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Whether this request's `Origin` (if any) may open `/stream`. Ordinary
+    /// responses rely on `cors_headers` because a browser only exposes a
+    /// cross-origin response to the page if the headers say so, but a
+    /// browser never withholds a WebSocket connection based on CORS headers
+    /// -- the server has to refuse the handshake itself before it completes.
+    /// A request with no `Origin` header (e.g. a non-browser client) is let
+    /// through, matching `cors_headers`' treatment of that case.
+    fn origin_is_allowed(&self, ctx: &Context) -> bool {
+        match self.origin() {
+            Some(origin) => ctx.cors_allowed_origin == "*" || ctx.cors_allowed_origin == origin,
+            None => true,
+        }
+    }
+
+    fn body(&mut self) -> Result<EditRequestData, Error> {
+        let body = if self.is_chunked() {
+            self.buffer
+                .get(self.header_offset..)
+                .ok_or_else(|| anyhow!("Request body is missing"))?
+        } else {
+            let body_length = self
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+                .ok_or_else(|| anyhow!("'Content-Length' header is missing"))
+                .and_then(|header| {
+                    std::str::from_utf8(header.value)
+                        .context("'Content-Length' header is not valid UTF-8")
+                })
+                .and_then(|header| {
+                    header
+                        .parse::<usize>()
+                        .context("'Content-Length' header is not a valid number")
+                })?;
+
+            let body_start = self.header_offset;
+            let body_end = body_start + body_length;
+            self.buffer
+                .get(body_start..body_end)
+                .ok_or_else(|| anyhow!("Request body is shorter than 'Content-Length'"))?
+        };
+
         let body_str = std::str::from_utf8(body).context("ReqParser body is not valid UTF-8")?;
         serde_json::from_str(body_str).context("ReqParser body is not valid JSON")
     }
@@ -169,27 +839,25 @@ struct EditRequestData {
 }
 
 impl EditRequestData {
-    fn wait_for_first_frame(&self, ctx: &mut Context) -> Result<Response, Error> {
-        let tc = ctx.decode_handlers.recv_frame()?;
-        ctx.cut_log
-            .push(tc, &self.edit_type, &self.source_tape, &self.av_channel)?;
-        Ok(format!("timecode logged: {:#?}", tc.timecode()).into())
-    }
-
-    fn try_log_edit(&self, ctx: &mut Context) -> Result<Response, Error> {
+    /// Called only from [`pump_frames`], which is the sole owner of
+    /// `ctx.decode_handlers`; never call this directly from a connection
+    /// handler thread.
+    fn try_log_edit(&self, ctx: &Context) -> Result<Response, Error> {
         match self.parse_edit_from_log(ctx) {
-            Ok(edit) => Ok(ctx.edl.write_from_edit(edit)?.into()),
+            Ok(edit) => Ok(lock(&ctx.edl)?.write_from_edit(edit)?.into()),
             Err(DecodeErr::NoVal(_)) => Ok(frame_unavailable()),
             Err(e) => Err(Error::msg(e)),
         }
     }
 
-    fn parse_edit_from_log(&self, ctx: &mut Context) -> Result<Edit, DecodeErr> {
-        let tc = ctx.decode_handlers.try_recv_frame()?;
-        ctx.cut_log
-            .push(tc, &self.edit_type, &self.source_tape, &self.av_channel)?;
-        let prev_record = ctx.cut_log.pop().context("No value in cut_log")?;
-        let curr_record = ctx.cut_log.front().context("No value in cut_log")?;
+    fn parse_edit_from_log(&self, ctx: &Context) -> Result<Edit, DecodeErr> {
+        let tc = lock(&ctx.decode_handlers)?.try_recv_frame()?;
+        // Hold a single lock across push/pop/front so another connection's
+        // request can't interleave a log entry between them.
+        let mut cut_log = lock(&ctx.cut_log)?;
+        cut_log.push(tc, &self.edit_type, &self.source_tape, &self.av_channel)?;
+        let prev_record = cut_log.pop().context("No value in cut_log")?;
+        let curr_record = cut_log.front().context("No value in cut_log")?;
         Ok(Edit::from_cuts(&prev_record, curr_record)?)
     }
 }
@@ -202,6 +870,8 @@ impl From<String> for Response {
         Response {
             status_line,
             content,
+            raw_json: false,
+            headers: Vec::new(),
         }
     }
 }
@@ -215,10 +885,15 @@ impl From<Response> for SerializedResponse {
         let content = value.content;
         let length = content.len();
         let status_line = value.status_line;
+        let extra_headers: String = value
+            .headers
+            .iter()
+            .map(|(name, val)| format!("{name}: {val}\r\n"))
+            .collect();
 
         SerializedResponse {
             value: format!(
-                "{status_line}\r\nContent-Type: application/json\r\nContent-Length: {length}\r\n\r\n{content}"
+                "{status_line}\r\nContent-Type: application/json\r\nContent-Length: {length}\r\n{extra_headers}\r\n{content}"
             ),
         }
     }
@@ -229,6 +904,8 @@ fn frame_unavailable() -> Response {
         status_line: "HTTP/1.1 200 OK".to_string(),
         content: "Unable to get timecode. Make sure source is streaming and decoding has started."
             .to_string(),
+        raw_json: false,
+        headers: Vec::new(),
     }
 }
 
@@ -236,6 +913,8 @@ fn server_err() -> Response {
     Response {
         status_line: "HTTP/1.1 500 INTERNAL SERVER ERROR".to_string(),
         content: "Failed to parse request".to_string(),
+        raw_json: false,
+        headers: Vec::new(),
     }
 }
 
@@ -243,5 +922,160 @@ fn not_found() -> Response {
     Response {
         status_line: "HTTP/1.1 404 NOT FOUND".to_string(),
         content: "Command not found".to_string(),
+        raw_json: false,
+        headers: Vec::new(),
+    }
+}
+
+fn method_not_allowed() -> Response {
+    Response {
+        status_line: "HTTP/1.1 405 METHOD NOT ALLOWED".to_string(),
+        content: "Method not allowed for this path".to_string(),
+        raw_json: false,
+        headers: Vec::new(),
+    }
+}
+
+fn bad_request() -> Response {
+    Response {
+        status_line: "HTTP/1.1 400 BAD REQUEST".to_string(),
+        content: "Request body is missing or malformed".to_string(),
+        raw_json: false,
+        headers: Vec::new(),
+    }
+}
+
+fn forbidden() -> Response {
+    Response {
+        status_line: "HTTP/1.1 403 FORBIDDEN".to_string(),
+        content: "Origin not allowed".to_string(),
+        raw_json: false,
+        headers: Vec::new(),
+    }
+}
+
+fn payload_too_large() -> Response {
+    Response {
+        status_line: "HTTP/1.1 413 PAYLOAD TOO LARGE".to_string(),
+        content: "Request exceeded maximum buffer size".to_string(),
+        raw_json: false,
+        headers: Vec::new(),
+    }
+}
+
+const CORS_ALLOWED_METHODS: &str = "GET, POST, DELETE, OPTIONS";
+const CORS_ALLOWED_HEADERS: &str = "Content-Type";
+
+/// Answers a CORS preflight `OPTIONS` request. `Access-Control-Allow-Origin`
+/// is attached separately by `Request::cors_headers`, same as for every
+/// other response, so an unrecognized origin gets a preflight with no
+/// allow-origin header and the browser blocks the real request.
+fn cors_preflight() -> Response {
+    Response {
+        status_line: "HTTP/1.1 200 OK".to_string(),
+        content: String::new(),
+        raw_json: true,
+        headers: vec![
+            ("Access-Control-Allow-Methods".to_string(), CORS_ALLOWED_METHODS.to_string()),
+            ("Access-Control-Allow-Headers".to_string(), CORS_ALLOWED_HEADERS.to_string()),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The canonical RFC 6455 section 1.3 handshake example.
+    #[test]
+    fn websocket_accept_key_matches_rfc6455_example() {
+        let accept = websocket_accept_key("dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn encode_text_frame_short_payload() {
+        let frame = encode_text_frame("Hi");
+        // FIN + text opcode, then an unmasked length byte, then the payload.
+        assert_eq!(frame, vec![0b1000_0001, 0x02, b'H', b'i']);
+    }
+
+    #[test]
+    fn encode_text_frame_extended_length_boundary() {
+        // 126 is the first length that needs the 2-byte extended form.
+        let payload = "a".repeat(126);
+        let frame = encode_text_frame(&payload);
+        assert_eq!(&frame[..4], &[0b1000_0001, 126, 0x00, 0x7e]);
+        assert_eq!(frame.len(), 4 + payload.len());
+    }
+
+    #[test]
+    fn match_path_captures_params_in_order() {
+        assert_eq!(
+            match_path("/log/:index", "/log/3"),
+            Some(vec!["3"])
+        );
+    }
+
+    #[test]
+    fn match_path_rejects_wrong_segment_count() {
+        assert_eq!(match_path("/log/:index", "/log/3/extra"), None);
+    }
+
+    #[test]
+    fn match_path_rejects_literal_mismatch() {
+        assert_eq!(match_path("/log/:index", "/edl/3"), None);
+    }
+
+    #[test]
+    fn match_path_exact_literal_match_has_no_params() {
+        assert_eq!(match_path("/edl", "/edl"), Some(vec![]));
+    }
+
+    /// Spins up a loopback `TcpListener`/`TcpStream` pair and sends each of
+    /// `writes` through the client half as its own write, pausing between
+    /// them, so `read_chunked_body` has a real `TcpStream` to read from --
+    /// there's no stream-abstraction trait in this module to mock it with.
+    fn read_chunked_body_over_loopback(writes: Vec<&'static [u8]>, header_len: usize) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let writer = thread::spawn(move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            for write in writes {
+                client.write_all(write).unwrap();
+                client.flush().unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let (mut server_stream, _) = listener.accept().unwrap();
+        let mut buffer = vec![0u8; header_len];
+        server_stream.read_exact(&mut buffer).unwrap();
+        let mut chunk = [0u8; 4096];
+        let body = read_chunked_body(&mut server_stream, &mut buffer, &mut chunk, header_len)
+            .unwrap_or_else(|_| panic!("read_chunked_body failed"));
+
+        writer.join().unwrap();
+        body
+    }
+
+    #[test]
+    fn read_chunked_body_decodes_multiple_chunks() {
+        let body = read_chunked_body_over_loopback(
+            vec![b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n".as_slice()],
+            0,
+        );
+        assert_eq!(body, b"Wikipedia");
+    }
+
+    #[test]
+    fn read_chunked_body_handles_chunk_extension_and_boundary_split() {
+        // A chunk-size line may carry a `;`-delimited extension that must be
+        // ignored, and the chunk data is sent in a second write so the reader
+        // has to pull more off the stream mid-chunk.
+        let writes = vec![b"3;foo=bar\r\n".as_slice(), b"abc\r\n0\r\n\r\n".as_slice()];
+        let body = read_chunked_body_over_loopback(writes, 0);
+        assert_eq!(body, b"abc");
     }
 }